@@ -1,31 +1,384 @@
-use fluentbase_build::{build_with_args, Artifact, BuildArgs};
-use std::path::PathBuf;
+use fluentbase_build::{build_with_args, rwasm_from_wasm, Artifact, BuildArgs};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const CACHE_FILE: &str = "out/.build-cache.json";
+
+/// Files produced by `build_with_args` for the `PowerCalculator` contract.
+const EXPECTED_OUTPUTS: &[&str] = &[
+    "PowerCalculator.wasm",
+    "PowerCalculator.rwasm",
+    "PowerCalculator.wat",
+    "PowerCalculator.abi.json",
+    "PowerCalculator.sol",
+    "PowerCalculator.metadata.json",
+];
+
+/// Recursively feeds every file under `dir` into `hasher`, in a stable
+/// (sorted) order so the digest doesn't depend on directory iteration order.
+fn hash_dir(hasher: &mut Sha256, dir: &Path) {
+    let mut entries: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(Result::ok).collect(),
+        Err(_) => return,
+    };
+    entries.sort_by_key(|e| e.path());
+
+    for entry in entries {
+        let path = entry.path();
+        if path.is_dir() {
+            hash_dir(hasher, &path);
+        } else if let Ok(contents) = fs::read(&path) {
+            hasher.update(path.to_string_lossy().as_bytes());
+            hasher.update(&contents);
+        }
+    }
+}
+
+/// Computes a composite digest over everything that can affect the build
+/// output: source files, lockfiles, the tag, the resolved Docker image, the
+/// wasm-opt profile, and the requested artifact list.
+fn compute_digest(
+    tag: &str,
+    docker_image: &str,
+    opt_profile: OptProfile,
+    args: &BuildArgs,
+) -> String {
+    let mut hasher = Sha256::new();
+
+    hash_dir(&mut hasher, Path::new("src"));
+    for f in ["Cargo.toml", "Cargo.lock"] {
+        if let Ok(contents) = fs::read(f) {
+            hasher.update(&contents);
+        }
+    }
+    hasher.update(tag.as_bytes());
+    hasher.update(docker_image.as_bytes());
+    hasher.update(&[args.docker as u8]);
+    hasher.update(opt_profile.as_str().as_bytes());
+    for artifact in &args.generate {
+        hasher.update(format!("{:?}", artifact).as_bytes());
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+/// Pulls a `"key": "value"` string field out of the cache's minimal JSON;
+/// avoids pulling in a JSON dependency just to read the digest back out.
+fn read_json_field(contents: &str, key: &str) -> Option<String> {
+    let needle = format!("\"{key}\"");
+    let start = contents.find(&needle)? + needle.len();
+    let start = contents[start..].find('"')? + start + 1;
+    let end = contents[start..].find('"')? + start;
+    Some(contents[start..end].to_string())
+}
+
+fn read_cached_digest() -> Option<String> {
+    let contents = fs::read_to_string(CACHE_FILE).ok()?;
+    read_json_field(&contents, "digest")
+}
+
+fn write_cache(digest: &str) {
+    if let Some(parent) = Path::new(CACHE_FILE).parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    let _ = fs::write(CACHE_FILE, format!("{{\"digest\": \"{digest}\"}}\n"));
+}
+
+fn outputs_present(out_dir: &Path) -> bool {
+    EXPECTED_OUTPUTS.iter().all(|f| out_dir.join(f).exists())
+}
+
+/// Whether a usable Docker daemon is reachable, used by `auto` mode to
+/// decide whether to take the containerized or native path.
+fn docker_available() -> bool {
+    Command::new("docker")
+        .arg("info")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Resolves `FLUENT_BUILD_MODE` (`docker` | `native` | `auto`, default
+/// `auto`) to whether the Docker-backed path should be used. Mirrors the
+/// in-tree-vs-external toolchain selection rustc's bootstrap uses to pick
+/// between a vendored and a system toolchain, but here the choice is
+/// Docker vs. a local `wasm32` toolchain.
+fn use_docker() -> bool {
+    match std::env::var("FLUENT_BUILD_MODE") {
+        Ok(mode) if mode == "docker" => true,
+        Ok(mode) if mode == "native" => false,
+        Ok(mode) if mode == "auto" => docker_available(),
+        Ok(mode) => {
+            println!("cargo:warning=unknown FLUENT_BUILD_MODE '{mode}', falling back to auto");
+            docker_available()
+        }
+        Err(_) => docker_available(),
+    }
+}
+
+/// Speed/size tradeoff for the wasm-opt pass. `Search` trades build time for
+/// the smallest achievable artifact, which matters because output size is
+/// what on-chain bytecode costs scale with.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OptProfile {
+    None,
+    Speed,
+    Size,
+    Search,
+}
+
+impl OptProfile {
+    fn from_env() -> Self {
+        match std::env::var("FLUENT_WASM_OPT_PROFILE").as_deref() {
+            Ok("none") => OptProfile::None,
+            Ok("speed") => OptProfile::Speed,
+            Ok("search") => OptProfile::Search,
+            Ok("size") => OptProfile::Size,
+            Err(_) => OptProfile::Size,
+            Ok(other) => {
+                println!(
+                    "cargo:warning=unknown FLUENT_WASM_OPT_PROFILE '{other}', falling back to size"
+                );
+                OptProfile::Size
+            }
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            OptProfile::None => "none",
+            OptProfile::Speed => "speed",
+            OptProfile::Size => "size",
+            OptProfile::Search => "search",
+        }
+    }
+
+    /// The single-pass `wasm-opt` flags for this profile. `None` means no
+    /// optimization; `Search` isn't a single pass, it's handled separately
+    /// by `search_smallest_wasm_opt`.
+    fn wasm_opt_flags(self) -> Option<&'static [&'static str]> {
+        match self {
+            OptProfile::None | OptProfile::Search => None,
+            OptProfile::Speed => Some(&["-O1"]),
+            OptProfile::Size => Some(&["-Os"]),
+        }
+    }
+}
+
+/// Candidate pass configurations tried in `search` mode, in the spirit of
+/// profile-guided optimization workflows for rustc dist builds, but applied
+/// statically to artifact size rather than runtime profiles.
+const SEARCH_CANDIDATES: &[(&str, &[&str])] = &[
+    ("size", &["-Oz"]),
+    ("aggressive", &["-O4"]),
+    ("flatten-rereloop", &["--flatten", "--rereloop"]),
+];
+
+/// Runs a single `wasm-opt` invocation with `flags` over `wasm_path` and
+/// returns the resulting bytes, or `None` if the tool failed to run.
+fn run_wasm_opt(wasm_path: &Path, tmp_path: &Path, flags: &[&str]) -> Option<Vec<u8>> {
+    let status = Command::new("wasm-opt")
+        .arg(wasm_path)
+        .args(flags)
+        .arg("-o")
+        .arg(tmp_path)
+        .status();
+
+    let result = match status {
+        Ok(s) if s.success() => fs::read(tmp_path).ok(),
+        _ => None,
+    };
+    let _ = fs::remove_file(tmp_path);
+    result
+}
+
+/// Regenerates `.rwasm` and `.wat` from a finalized wasm module, so those
+/// derived artifacts always match what wasm-opt (single-pass or search)
+/// actually produced, instead of the pre-optimization build still sitting
+/// next to it. Panics rather than leaving a stale `.rwasm`/`.wat` next to
+/// the now-optimized `.wasm` — a mismatched pair is worse than a failed
+/// build, since it would silently ship.
+fn regenerate_derived_artifacts(out_dir: &Path, wasm_bytes: &[u8]) {
+    match rwasm_from_wasm(wasm_bytes) {
+        Ok(rwasm_bytes) => {
+            fs::write(out_dir.join("PowerCalculator.rwasm"), rwasm_bytes)
+                .expect("failed to write regenerated .rwasm");
+        }
+        Err(err) => {
+            panic!("failed to regenerate .rwasm from optimized wasm: {err}");
+        }
+    }
+
+    let status = Command::new("wasm2wat")
+        .arg(out_dir.join("PowerCalculator.wasm"))
+        .arg("-o")
+        .arg(out_dir.join("PowerCalculator.wat"))
+        .status();
+    if !matches!(status, Ok(s) if s.success()) {
+        panic!("failed to regenerate .wat from optimized wasm");
+    }
+}
+
+/// Writes `bytes` as the final `PowerCalculator.wasm` and brings `.rwasm`
+/// and `.wat` back in sync with it.
+fn finalize_optimized_wasm(out_dir: &Path, bytes: Vec<u8>) {
+    fs::write(out_dir.join("PowerCalculator.wasm"), &bytes)
+        .expect("failed to write optimized .wasm");
+    regenerate_derived_artifacts(out_dir, &bytes);
+}
+
+/// Runs the single-pass profile's (`speed` or `size`) wasm-opt flags
+/// directly, so the two profiles are actually distinguishable instead of
+/// both just flipping the same `wasm_opt` bool.
+fn apply_single_pass(out_dir: &Path, flags: &[&str]) {
+    let wasm_path = out_dir.join("PowerCalculator.wasm");
+    let tmp_path = out_dir.join("PowerCalculator.opt.tmp.wasm");
+
+    match run_wasm_opt(&wasm_path, &tmp_path, flags) {
+        Some(bytes) => {
+            println!(
+                "cargo:warning=wasm-opt ({}): {} bytes",
+                flags.join(" "),
+                bytes.len()
+            );
+            finalize_optimized_wasm(out_dir, bytes);
+        }
+        None => println!("cargo:warning=wasm-opt invocation failed, keeping unoptimized wasm"),
+    }
+}
+
+/// Runs each candidate `wasm-opt` pass configuration over the built wasm,
+/// keeps whichever output is smallest, and reports every candidate's size.
+fn search_smallest_wasm_opt(out_dir: &Path) {
+    let wasm_path = out_dir.join("PowerCalculator.wasm");
+    let Ok(original) = fs::read(&wasm_path) else {
+        return;
+    };
+
+    let mut best: Option<(&str, Vec<u8>)> = None;
+
+    for (name, flags) in SEARCH_CANDIDATES {
+        let tmp_path = out_dir.join(format!("PowerCalculator.search-{name}.wasm"));
+        match run_wasm_opt(&wasm_path, &tmp_path, flags) {
+            Some(bytes) => {
+                println!("cargo:warning=wasm-opt search '{name}': {} bytes", bytes.len());
+                if best.as_ref().map_or(true, |(_, b)| bytes.len() < b.len()) {
+                    best = Some((name, bytes));
+                }
+            }
+            None => println!("cargo:warning=wasm-opt search '{name}' failed, skipping"),
+        }
+    }
+
+    match best {
+        Some((name, bytes)) if bytes.len() < original.len() => {
+            println!(
+                "cargo:warning=wasm-opt search: selecting '{name}' ({} bytes, was {} bytes)",
+                bytes.len(),
+                original.len()
+            );
+            finalize_optimized_wasm(out_dir, bytes);
+        }
+        _ => println!(
+            "cargo:warning=wasm-opt search: no candidate smaller than the existing {} bytes",
+            original.len()
+        ),
+    }
+}
+
+/// Hashes the produced rWASM, persists the digest alongside it for
+/// attestation, and, if `FLUENT_VERIFY_AGAINST` names an expected digest,
+/// fails the build on mismatch so non-determinism from `wasm_opt` or
+/// toolchain drift gets caught instead of silently shipped.
+fn verify_rwasm(out_dir: &Path) {
+    let rwasm_path = out_dir.join("PowerCalculator.rwasm");
+    let Ok(contents) = fs::read(&rwasm_path) else {
+        return;
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(&contents);
+    let digest = format!("{:x}", hasher.finalize());
+
+    let _ = fs::write(out_dir.join("PowerCalculator.rwasm.sha256"), format!("{digest}\n"));
+
+    if let Ok(expected) = std::env::var("FLUENT_VERIFY_AGAINST") {
+        if expected != digest {
+            panic!(
+                "reproducible-build verification failed: expected rWASM digest {expected}, got {digest}"
+            );
+        }
+        println!("cargo:warning=reproducible-build verification passed ({digest})");
+    }
+}
 
 fn main() {
     println!("cargo:warning=Build script started");
 
     // std::env::set_var("FLUENT_DOCKER_IMAGE", "fluentbase:local");
 
-    build_with_args(
-        ".",
-        BuildArgs {
-            contract_name: Some("PowerCalculator.wasm".to_string()),
-            docker: true,
-            tag: "v0.3.4-dev".to_string(),
-            mount_dir: Some(PathBuf::from("./")),
-            output: Some(PathBuf::from("out")),
-            generate: vec![
-                Artifact::Metadata,
-                Artifact::Rwasm,
-                Artifact::Wat,
-                Artifact::Solidity,
-                Artifact::Abi,
-            ],
-            wasm_opt: true,
-            locked: true,
-            ..Default::default()
-        },
+    let docker_image =
+        std::env::var("FLUENT_DOCKER_IMAGE").unwrap_or_else(|_| "default".to_string());
+    let tag = "v0.3.4-dev".to_string();
+    let out_dir = PathBuf::from("out");
+
+    let docker = use_docker();
+    println!(
+        "cargo:warning=build mode: {}",
+        if docker { "docker" } else { "native" }
     );
 
+    let opt_profile = OptProfile::from_env();
+    println!("cargo:warning=wasm-opt profile: {}", opt_profile.as_str());
+
+    let args = BuildArgs {
+        contract_name: Some("PowerCalculator.wasm".to_string()),
+        docker,
+        tag: tag.clone(),
+        mount_dir: Some(PathBuf::from("./")),
+        output: Some(out_dir.clone()),
+        generate: vec![
+            Artifact::Metadata,
+            Artifact::Rwasm,
+            Artifact::Wat,
+            Artifact::Solidity,
+            Artifact::Abi,
+        ],
+        // wasm-opt is driven directly by `apply_single_pass`/
+        // `search_smallest_wasm_opt` below so each profile gets its own
+        // flags instead of a single shared bool.
+        wasm_opt: false,
+        locked: true,
+        ..Default::default()
+    };
+
+    let digest = compute_digest(&tag, &docker_image, opt_profile, &args);
+    let cache_hit =
+        read_cached_digest().as_deref() == Some(digest.as_str()) && outputs_present(&out_dir);
+
+    if cache_hit {
+        println!("cargo:warning=build cache hit");
+    } else {
+        build_with_args(".", args);
+
+        match opt_profile {
+            OptProfile::None => {}
+            OptProfile::Search => search_smallest_wasm_opt(&out_dir),
+            OptProfile::Speed | OptProfile::Size => {
+                let flags = opt_profile
+                    .wasm_opt_flags()
+                    .expect("Speed/Size profiles always have wasm-opt flags");
+                apply_single_pass(&out_dir, flags);
+            }
+        }
+
+        write_cache(&digest);
+    }
+
+    verify_rwasm(&out_dir);
+
     println!("cargo:warning=Build script completed");
 }